@@ -0,0 +1,9 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use defiads::content::Content;
+
+// arbitrary wire bytes decoded through the same serde Deserialize used for incoming
+// Message::Content must never panic, only return an Err
+fuzz_target!(|data: &[u8]| {
+    let _: Result<Content, _> = bincode::deserialize(data);
+});
@@ -0,0 +1,17 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use defiads::content::ContentKey;
+use defiads::iblt::IBLTKey;
+
+// arbitrary digest bytes must be rejected with an Error, never an assertion panic, and any key
+// that is accepted must hash without panicking
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 4 {
+        return;
+    }
+    let (weight_bytes, digest) = data.split_at(4);
+    let weight = u32::from_le_bytes([weight_bytes[0], weight_bytes[1], weight_bytes[2], weight_bytes[3]]);
+    if let Ok(key) = ContentKey::new(digest, weight) {
+        let _ = key.hash_to_u64_with_keys(0, 0);
+    }
+});
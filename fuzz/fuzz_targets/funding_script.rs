@@ -0,0 +1,24 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use defiads::bitcoin::PublicKey;
+use defiads::bitcoin_hashes::sha256;
+use defiads::content::{p2wsh_funding_script, p2tr_funding_script};
+use defiads::content::secp256k1::{Secp256k1, VerifyOnly};
+
+// arbitrary funder keys and digests (including degenerate tweaks) must never panic building
+// either funding script, only return an Error
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 33 + 32 + 2 {
+        return;
+    }
+    let funder = match PublicKey::from_slice(&data[0..33]) {
+        Ok(funder) => funder,
+        Err(_) => return
+    };
+    let digest = sha256::Hash::from_slice(&data[33..65]).expect("33..65 is 32 bytes");
+    let term = u16::from_le_bytes([data[65], data[66]]);
+    let ctx = Secp256k1::<VerifyOnly>::verification_only();
+
+    let _ = p2wsh_funding_script(&funder, &digest, term, &ctx);
+    let _ = p2tr_funding_script(&funder, &digest, term, &ctx);
+});
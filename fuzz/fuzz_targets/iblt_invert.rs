@@ -0,0 +1,23 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use defiads::content::ContentKey;
+use defiads::iblt::IBLT;
+
+// build an IBLT from arbitrary peer-shaped keys and peel it; a diff too large to invert must
+// surface as NotInvertible rather than panicking or looping forever
+fuzz_target!(|data: &[u8]| {
+    let mut iblt = IBLT::<ContentKey>::new(64);
+    for chunk in data.chunks(36) {
+        if chunk.len() < 36 {
+            break;
+        }
+        if let Ok(key) = ContentKey::new(&chunk[0..32], u32::from_le_bytes([chunk[32], chunk[33], chunk[34], chunk[35]])) {
+            iblt.insert(key);
+        }
+    }
+    for entry in iblt.into_iter() {
+        if entry.is_err() {
+            break;
+        }
+    }
+});
@@ -30,6 +30,10 @@ use crate::bitcoin_hashes::{
 
 use secp256k1::{Secp256k1, Signature, VerifyOnly, Message};
 
+/// re-exported so downstream crates (e.g. the fuzz targets) build against the exact
+/// `secp256k1` this crate resolved, instead of pinning a possibly-diverging version of their own
+pub use secp256k1;
+
 use crate::iblt::IBLTKey;
 use crate::serde::{Serialize, Deserialize, Serializer, Deserializer};
 
@@ -71,14 +75,45 @@ impl fmt::Debug for ContentKey {
 }
 
 impl ContentKey {
-    pub fn new (hash: &[u8], weight: u32) -> ContentKey {
-        assert_eq!(hash.len(), DIGEST_LEN);
+    pub fn new (hash: &[u8], weight: u32) -> Result<ContentKey, Error> {
+        if hash.len() != DIGEST_LEN {
+            return Err(Error::InvalidDigestLength(hash.len()));
+        }
         let mut digest = [0u8; DIGEST_LEN];
         digest.copy_from_slice(&hash[..]);
-        ContentKey{digest, weight}
+        Ok(ContentKey{digest, weight})
+    }
+}
+
+/// errors raised while decoding or validating content and keys coming from a peer
+#[derive(Debug)]
+pub enum Error {
+    /// a digest did not have the expected length
+    InvalidDigestLength(usize),
+    /// tweaking a public key produced the point at infinity
+    InvalidTweak
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidDigestLength(len) => write!(f, "invalid digest length {} expected {}", len, DIGEST_LEN),
+            Error::InvalidTweak => write!(f, "public key tweak produced an invalid point")
+        }
     }
 }
 
+impl error::Error for Error {}
+
+/// the script format used to commit the funding output of an ad
+#[derive(Clone, Copy, Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub enum FundingMode {
+    /// a P2WSH output pushing the digest-tweaked funder key and the encoded timelock
+    P2WSH,
+    /// a single-leaf P2TR output with the funder key as internal key
+    P2TR
+}
+
 /// replicated content
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Content {
@@ -93,7 +128,9 @@ pub struct Content {
     /// funder
     pub funder: PublicKey,
     /// term of funding in blocks
-    pub term: u16
+    pub term: u16,
+    /// script format used to commit the funding output
+    pub funding_mode: FundingMode
 }
 
 impl Content {
@@ -121,10 +158,13 @@ impl Content {
         }) == *merkle_root
     }
 
-    /// check if the funding transaction really funds this ad
+    /// check if the funding transaction really funds this ad; a funder key that can not be
+    /// tweaked (e.g. a maliciously crafted near-infinity point) is simply not valid funding
     pub fn is_valid_funding (&self, ctx: &Secp256k1<VerifyOnly>) -> bool {
-        let f_script = funding_script(&self.funder, &self.digest(), self.term, ctx);
-        self.funding.output.iter().any(|o| o.script_pubkey == f_script)
+        match funding_script(&self.funder, &self.digest(), self.term, self.funding_mode, ctx) {
+            Ok(f_script) => self.funding.output.iter().any(|o| o.script_pubkey == f_script),
+            Err(_) => false
+        }
     }
 
     pub fn is_valid (&self, merkle_root: &sha256d::Hash, ctx: &Secp256k1<VerifyOnly>) -> bool {
@@ -132,26 +172,161 @@ impl Content {
     }
 
     pub fn weight (&self, ctx: &Secp256k1<VerifyOnly>) -> u32 {
-        let f_script = funding_script(&self.funder, &self.digest(), self.term, ctx);
+        let f_script = match funding_script(&self.funder, &self.digest(), self.term, self.funding_mode, ctx) {
+            Ok(f_script) => f_script,
+            Err(_) => return 0
+        };
 
         (self.funding.output.iter().filter_map(|o| if o.script_pubkey == f_script { Some(o.value)} else {None}).sum::<u64>()
             /
         (self.data.len() + consensus::serialize(&self.funding).len() + self.spv_proof.len() * 32usize) as u64) as u32
     }
+
+    /// blocks remaining before this content's funding term expires, given the height the
+    /// funding transaction was confirmed at (derived from `block_id`) and the current tip
+    pub fn remaining (&self, funding_height: u32, tip_height: u32) -> i64 {
+        self.term as i64 - (tip_height as i64 - funding_height as i64)
+    }
+
+    /// true once `remaining` drops to or below zero, i.e. the ad's term has run out
+    pub fn is_expired (&self, funding_height: u32, tip_height: u32) -> bool {
+        self.remaining(funding_height, tip_height) <= 0
+    }
+
+    /// weight scaled down as the term runs out, tied to the chain tip
+    ///
+    /// the scaling factor is bucketed into `WEIGHT_DECAY_BUCKETS` steps instead of computed as a
+    /// continuous `remaining / term` ratio, so the resulting `ContentKey` (and with it the IBLT
+    /// sketch) only needs to be rebuilt a handful of times over an ad's term rather than on every
+    /// block
+    pub fn decayed_weight (&self, ctx: &Secp256k1<VerifyOnly>, funding_height: u32, tip_height: u32) -> u32 {
+        let remaining = self.remaining(funding_height, tip_height);
+        if remaining <= 0 || self.term == 0 {
+            return 0;
+        }
+        let bucket = (remaining as u64 * WEIGHT_DECAY_BUCKETS as u64 / self.term as u64).min(WEIGHT_DECAY_BUCKETS as u64 - 1) + 1;
+        (self.weight(ctx) as u64 * bucket / WEIGHT_DECAY_BUCKETS as u64) as u32
+    }
+}
+
+/// number of discrete steps used to decay weight as an ad's funding term runs out
+pub const WEIGHT_DECAY_BUCKETS: u32 = 16;
+
+/// build the script that commits the funding output of an ad for the given mode
+///
+/// won't-fix (chunk0-1): that request asked to thread a `Network` through this function,
+/// `Content::is_valid_funding`/`weight`, and the `Updater` constructor, on the theory that a node
+/// configured for testnet/signet/regtest was "silently rejecting all content because the address
+/// prefix never matches". That premise does not hold: a scriptPubkey (what every validator here
+/// compares) never encodes the network — only the bech32/base58 *address string* does, via
+/// `Address::to_string()`, which nothing in this crate's validation path calls. There is no
+/// network-dependent behavior here to thread a parameter through, so this stays as-is
+pub fn funding_script (funder: &PublicKey, digest: &sha256::Hash, term: u16, mode: FundingMode, ctx: &Secp256k1<VerifyOnly>) -> Result<Script, Error> {
+    match mode {
+        FundingMode::P2WSH => p2wsh_funding_script(funder, digest, term, ctx),
+        FundingMode::P2TR => p2tr_funding_script(funder, digest, term, ctx)
+    }
+}
+
+/// tweak a public key by a scalar, returning `Error::InvalidTweak` instead of panicking when the
+/// peer-supplied key or digest happens to produce an invalid point
+fn tweak_key (key: &PublicKey, tweak: &[u8], ctx: &Secp256k1<VerifyOnly>) -> Result<PublicKey, Error> {
+    let mut tweaked = key.clone();
+    tweaked.key.add_exp_assign(ctx, tweak).map_err(|_| Error::InvalidTweak)?;
+    Ok(tweaked)
 }
 
-pub fn funding_script (funder: &PublicKey, digest: &sha256::Hash, term: u16, ctx: &Secp256k1<VerifyOnly>) -> Script {
-    let mut tweaked = funder.clone();
-    tweaked.key.add_exp_assign(ctx, &digest[..]).unwrap();
+/// build the timelocked commitment script shared by both funding modes
+fn commitment_script (tweaked: &PublicKey, term: u16, taproot: bool) -> Script {
+    // bit 22 is SEQUENCE_LOCKTIME_TYPE_FLAG (BIP68/112): set, OP_CHECKSEQUENCEVERIFY reads the
+    // value as 512-second units; clear, it reads as a block count. `term` is a block-based
+    // funding term (see Content::term), so the flag must stay clear - only widen to u32 so the
+    // value itself isn't truncated before being pushed as the 3-byte CSV argument
     let mut buf = [0u8; 4];
-    LittleEndian::write_u16(&mut buf, term | (1 << 22));
+    LittleEndian::write_u32(&mut buf, term as u32);
+
+    if taproot {
+        Builder::new()
+            .push_slice(&buf[0..3])
+            .push_opcode(all::OP_NOP3) // OP_CHECKSEQUENCEVERIFY
+            .push_opcode(all::OP_DROP)
+            .push_slice(&x_only(tweaked))
+            .push_opcode(all::OP_CHECKSIG)
+            .into_script()
+    }
+    else {
+        Builder::new()
+            .push_slice(tweaked.to_bytes().as_slice())
+            .push_opcode(all::OP_CHECKSIGVERIFY)
+            .push_slice(&buf[0..3])
+            .push_opcode(all::OP_NOP3) // OP_CHECKSEQUENCEVERIFY
+            .into_script()
+    }
+}
+
+/// commit the digest+term as a P2WSH output pushing the digest-tweaked funder key
+pub fn p2wsh_funding_script (funder: &PublicKey, digest: &sha256::Hash, term: u16, ctx: &Secp256k1<VerifyOnly>) -> Result<Script, Error> {
+    let tweaked = tweak_key(funder, &digest[..], ctx)?;
+
+    let script = commitment_script(&tweaked, term, false);
+
+    // the network only selects the address string's human-readable prefix; the scriptPubkey
+    // this produces is identical on every network, so any `Network` works here
+    Ok(Address::p2wsh(&script, Network::Bitcoin).script_pubkey())
+}
+
+/// commit the digest+term as a single-leaf P2TR output with the funder key as internal key
+pub fn p2tr_funding_script (funder: &PublicKey, digest: &sha256::Hash, term: u16, ctx: &Secp256k1<VerifyOnly>) -> Result<Script, Error> {
+    let tweaked = tweak_key(funder, &digest[..], ctx)?;
+
+    let leaf_script = commitment_script(&tweaked, term, true);
+    let merkle_root = tap_leaf_hash(&leaf_script);
+
+    let internal_key = x_only(funder);
+    let tap_tweak = tagged_hash(b"TapTweak", &[&internal_key[..], &merkle_root[..]].concat());
+
+    let output_key = tweak_key(&lift_x(&internal_key)?, &tap_tweak[..], ctx)?;
 
     let script = Builder::new()
-        .push_slice(tweaked.to_bytes().as_slice())
-        .push_opcode(all::OP_CHECKSIGVERIFY)
-        .push_slice(&buf[0..3])
-        .push_opcode(all::OP_NOP3) // OP_CHECKSEQUENCEVERIFY
+        .push_opcode(all::OP_PUSHNUM_1) // segwit version 1 (taproot)
+        .push_slice(&x_only(&output_key))
         .into_script();
 
-    Address::p2wsh(&script, Network::Bitcoin).script_pubkey()
+    Ok(script)
+}
+
+/// BIP340/341 tagged hash: SHA256(SHA256(tag) || SHA256(tag) || msg)
+fn tagged_hash (tag: &[u8], msg: &[u8]) -> sha256::Hash {
+    let tag_hash = sha256::Hash::hash(tag);
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag_hash[..]);
+    engine.input(&tag_hash[..]);
+    engine.input(msg);
+    sha256::Hash::from_engine(engine)
+}
+
+/// BIP341 tapleaf hash for a single tapscript leaf
+fn tap_leaf_hash (script: &Script) -> sha256::Hash {
+    const LEAF_VERSION_TAPSCRIPT: u8 = 0xc0;
+    let script_bytes = script.as_bytes();
+    let mut msg = Vec::with_capacity(2 + script_bytes.len());
+    msg.push(LEAF_VERSION_TAPSCRIPT);
+    msg.push(script_bytes.len() as u8);
+    msg.extend_from_slice(script_bytes);
+    tagged_hash(b"TapLeaf", &msg)
+}
+
+/// x-only (BIP340) serialization of a public key, i.e. its compressed form without the parity byte
+fn x_only (key: &PublicKey) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&key.to_bytes()[1..33]);
+    out
+}
+
+/// lift a 32 byte x-only key to the even-y point it represents, per BIP340
+fn lift_x (x: &[u8; 32]) -> Result<PublicKey, Error> {
+    let mut compressed = [0u8; 33];
+    compressed[0] = 0x02;
+    compressed[1..].copy_from_slice(&x[..]);
+    PublicKey::from_slice(&compressed).map_err(|_| Error::InvalidTweak)
 }
\ No newline at end of file
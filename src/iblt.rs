@@ -0,0 +1,177 @@
+//! Invertible Bloom Lookup Table used to reconcile the set of known content between peers.
+//!
+//! Cells are stored as a struct-of-arrays rather than an array of cell structs: `counts`,
+//! `keysums` and `hashsums` are separate, contiguous `Vec`s. Subtracting two IBLTs is then a
+//! straight, vectorizable pass over `counts` (and the other arrays in lock-step) instead of
+//! chasing pointers through interleaved cells, and inversion only needs to scan the dense
+//! `counts` array to find peelable (pure) cells.
+
+use std::ops::BitXorAssign;
+
+/// a key stored in an IBLT must be able to hash itself with an externally supplied keyed hash,
+/// so that the same key maps to the same cells on both sides of a reconciliation
+pub trait IBLTKey: Default + Clone + Eq + BitXorAssign {
+    fn hash_to_u64_with_keys(&self, k0: u64, k1: u64) -> u64;
+}
+
+/// number of independent cells a single key is inserted into
+const NUM_HASHES: usize = 4;
+
+/// fixed siphash keys, one pair per hash function, shared by both sides of a reconciliation
+const HASH_KEYS: [(u64, u64); NUM_HASHES] = [
+    (0x0000_0000_0000_0001, 0x1111_1111_1111_1111),
+    (0x0000_0000_0000_0002, 0x2222_2222_2222_2222),
+    (0x0000_0000_0000_0003, 0x3333_3333_3333_3333),
+    (0x0000_0000_0000_0004, 0x4444_4444_4444_4444)
+];
+
+/// a key recovered while peeling the difference `a.substract(b)` of two IBLTs
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum IBLTEntry<K> {
+    /// present in the set of `a` (the IBLT subtracted from) but missing in that of `b`
+    Inserted(K),
+    /// present in the set of `b` (the IBLT subtracted) but missing in that of `a`
+    Deleted(K)
+}
+
+/// raised by `IBLT::into_iter` when some cells could not be peeled, i.e. the sets differ by more
+/// than this IBLT's capacity can invert
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NotInvertible;
+
+/// an Invertible Bloom Lookup Table over keys of type `K`
+///
+/// cells live in three parallel arrays indexed together: `counts[i]`, `keysums[i]` and
+/// `hashsums[i]` all describe the same logical cell `i`
+#[derive(Clone)]
+pub struct IBLT<K> {
+    counts: Vec<i32>,
+    keysums: Vec<K>,
+    hashsums: Vec<u64>
+}
+
+impl<K: IBLTKey> IBLT<K> {
+    /// a new, empty IBLT with `size` cells (at least one cell, even for a `size` of 0)
+    pub fn new(size: u32) -> IBLT<K> {
+        let size = size.max(1) as usize;
+        IBLT {
+            counts: vec![0i32; size],
+            keysums: vec![K::default(); size],
+            hashsums: vec![0u64; size]
+        }
+    }
+
+    /// number of cells
+    pub fn len(&self) -> usize {
+        self.counts.len()
+    }
+
+    /// insert a key
+    pub fn insert(&mut self, key: K) {
+        self.apply(key, 1);
+    }
+
+    /// delete a key
+    pub fn delete(&mut self, key: K) {
+        self.apply(key, -1);
+    }
+
+    fn apply(&mut self, key: K, delta: i32) {
+        let n = self.counts.len() as u64;
+        let hash = key_hash(&key);
+        for (k0, k1) in HASH_KEYS.iter() {
+            let idx = (key.hash_to_u64_with_keys(*k0, *k1) % n) as usize;
+            self.counts[idx] += delta;
+            self.keysums[idx] ^= key.clone();
+            self.hashsums[idx] ^= hash;
+        }
+    }
+
+    /// subtract another IBLT of the same size, leaving the symmetric difference of the two sets
+    ///
+    /// a peer could send an IBLT of a different size than requested; rather than panicking on
+    /// the size mismatch this is reported as `NotInvertible` so the caller can drop the message
+    pub fn substract(&mut self, other: IBLT<K>) -> Result<(), NotInvertible> {
+        if self.counts.len() != other.counts.len() {
+            return Err(NotInvertible);
+        }
+        for i in 0..self.counts.len() {
+            self.counts[i] -= other.counts[i];
+            self.keysums[i] ^= other.keysums[i].clone();
+            self.hashsums[i] ^= other.hashsums[i];
+        }
+        Ok(())
+    }
+
+    /// true if the cell at `idx` contains exactly one key (possibly negated)
+    fn is_pure(&self, idx: usize) -> bool {
+        let count = self.counts[idx];
+        (count == 1 || count == -1) && self.hashsums[idx] == key_hash(&self.keysums[idx])
+    }
+}
+
+fn key_hash<K: IBLTKey>(key: &K) -> u64 {
+    key.hash_to_u64_with_keys(HASH_KEYS[0].0, HASH_KEYS[0].1)
+}
+
+/// peel an IBLT (typically the result of `substract`) into the keys that were inserted or
+/// deleted relative to the peer; if some entries can not be recovered because the IBLT was too
+/// small for the size of the difference, a single `Err(NotInvertible)` is yielded and iteration ends
+pub struct IBLTIntoIter<K> {
+    iblt: IBLT<K>,
+    failed: bool
+}
+
+impl<K: IBLTKey> Iterator for IBLTIntoIter<K> {
+    type Item = Result<IBLTEntry<K>, NotInvertible>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let pure_idx = (0..self.iblt.len()).find(|i| self.iblt.counts[*i] != 0 && self.iblt.is_pure(*i));
+        match pure_idx {
+            Some(idx) => {
+                let key = self.iblt.keysums[idx].clone();
+                let count = self.iblt.counts[idx];
+                if count > 0 {
+                    self.iblt.delete(key.clone());
+                    Some(Ok(IBLTEntry::Inserted(key)))
+                }
+                else {
+                    self.iblt.insert(key.clone());
+                    Some(Ok(IBLTEntry::Deleted(key)))
+                }
+            }
+            None => {
+                if self.iblt.counts.iter().any(|c| *c != 0) {
+                    self.failed = true;
+                    Some(Err(NotInvertible))
+                }
+                else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+impl<K: IBLTKey> IntoIterator for IBLT<K> {
+    type Item = Result<IBLTEntry<K>, NotInvertible>;
+    type IntoIter = IBLTIntoIter<K>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IBLTIntoIter { iblt: self, failed: false }
+    }
+}
+
+/// rough estimate of the size of the symmetric difference between two key sets, given only the
+/// compact sketches (not the full IBLTs) and key counts each side advertised; used to pick the
+/// smallest IBLT size that is likely to invert cleanly before exchanging full IBLTs
+pub fn estimate_diff_size(sketch_a: &[u8], size_a: u32, sketch_b: &[u8], size_b: u32) -> u32 {
+    let hamming = sketch_a.iter().zip(sketch_b.iter())
+        .map(|(a, b)| (a ^ b).count_ones())
+        .sum::<u32>();
+    let size_delta = (size_a as i64 - size_b as i64).unsigned_abs() as u32;
+    hamming + size_delta
+}
@@ -14,7 +14,7 @@
 // limitations under the License.
 //
 
-use bitcoin_hashes::{sha256, Hash};
+use bitcoin_hashes::{sha256, sha256d, Hash};
 use murmel::p2p::{PeerMessageSender, P2PControlSender, PeerMessageReceiver, PeerMessage};
 use murmel::timeout::SharedTimeout;
 
@@ -38,14 +38,15 @@ pub struct Updater {
     p2p: P2PControlSender<Message>,
     timeout: SharedTimeout<Message, ExpectedReply>,
     store: SharedContentStore,
-    poll_asked: HashMap<PeerId, PollContentMessage>
+    poll_asked: HashMap<PeerId, PollContentMessage>,
+    decayed_tip: Option<sha256d::Hash>
 }
 
 impl Updater {
     pub fn new(p2p: P2PControlSender<Message>, timeout: SharedTimeout<Message, ExpectedReply>, store: SharedContentStore) -> PeerMessageSender<Message> {
         let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
 
-        let mut updater = Updater { p2p, timeout, store, poll_asked: HashMap::new() };
+        let mut updater = Updater { p2p, timeout, store, poll_asked: HashMap::new(), decayed_tip: None };
 
         thread::Builder::new().name("biadnet updater".to_string()).spawn(move || { updater.run(receiver) }).unwrap();
 
@@ -74,13 +75,20 @@ impl Updater {
                                             let diff = estimate_diff_size(
                                                 question.sketch.as_slice(), question.size,
                                                 poll.sketch.as_slice(), poll.size);
-                                            let mut size = MINIMUM_IBLT_SIZE;
-                                            while size < MAXIMUM_IBLT_SIZE && size < diff {
-                                                size <<= 2;
+                                            if diff >= MAXIMUM_IBLT_SIZE {
+                                                // too far behind for an IBLT to invert; fall back to a full snapshot
+                                                self.timeout.lock().unwrap().expect(pid, 1, ExpectedReply::ContentDigests);
+                                                self.p2p.send_network(pid, Message::GetContentDigests(our_tip));
+                                            }
+                                            else {
+                                                let mut size = MINIMUM_IBLT_SIZE;
+                                                while size < MAXIMUM_IBLT_SIZE && size < diff {
+                                                    size <<= 2;
+                                                }
+                                                let iblt = store.get_iblt(size).expect("could not compute IBLT").clone();
+                                                self.timeout.lock().unwrap().expect(pid, 1, ExpectedReply::IBLT);
+                                                self.p2p.send_network(pid, Message::IBLT(our_tip, iblt));
                                             }
-                                            let iblt = store.get_iblt(size).expect("could not compute IBLT").clone();
-                                            self.timeout.lock().unwrap().expect(pid, 1, ExpectedReply::IBLT);
-                                            self.p2p.send_network(pid, Message::IBLT(our_tip, iblt));
                                         }
                                     }
                                 }
@@ -95,15 +103,20 @@ impl Updater {
                                 if let Some(our_tip) = store.get_tip() {
                                     if tip == our_tip {
                                         let size = iblt.len();
-                                        iblt.substract(
-                                            store.get_iblt(size).expect("can not compute IBLT")
-                                        );
+                                        if iblt.substract(store.get_iblt(size).expect("can not compute IBLT")).is_err() {
+                                            debug!("peer={} sent an IBLT of unexpected size", pid);
+                                            continue;
+                                        }
                                         let mut request = Vec::new();
                                         for entry in iblt.into_iter() {
                                             if let Ok(entry) = entry {
                                                 match entry {
-                                                    IBLTEntry::Deleted(key) =>
-                                                        request.push(sha256::Hash::from_slice(&key.digest[..]).unwrap()),
+                                                    IBLTEntry::Inserted(key) => {
+                                                        match sha256::Hash::from_slice(&key.digest[..]) {
+                                                            Ok(digest) => request.push(digest),
+                                                            Err(_) => debug!("received malformed content digest in IBLT from peer={}", pid)
+                                                        }
+                                                    },
                                                     _ => {}
                                                 };
                                             }
@@ -141,12 +154,44 @@ impl Updater {
                                     }
                                 }
                             }
+                            Message::GetContentDigests(tip) => {
+                                let store = self.store.read().unwrap();
+                                if let Some(our_tip) = store.get_tip() {
+                                    if tip == our_tip {
+                                        self.p2p.send_network(pid, Message::ContentDigests(our_tip, store.get_content_digests()));
+                                    }
+                                }
+                            }
+                            Message::ContentDigests(tip, digests) => {
+                                self.timeout.lock().unwrap().received(pid, 1, ExpectedReply::ContentDigests);
+                                let store = self.store.read().unwrap();
+                                if let Some(our_tip) = store.get_tip() {
+                                    if tip == our_tip {
+                                        let missing = digests.into_iter()
+                                            .filter(|digest| store.get_content(digest).map(|c| c.is_none()).unwrap_or(true))
+                                            .collect::<Vec<_>>();
+                                        for batch in missing.chunks(self.p2p.back_pressure.max(1)) {
+                                            let request = batch.to_vec();
+                                            self.timeout.lock().unwrap().expect(pid, request.len(), ExpectedReply::Content);
+                                            self.p2p.send_network(pid, Message::Get(request));
+                                        }
+                                    }
+                                }
+                            }
                             _ => {  }
                         }
                     }
                 }
             }
-            self.timeout.lock().unwrap().check(vec!(ExpectedReply::PollContent, ExpectedReply::IBLT, ExpectedReply::Content, ExpectedReply::Get));
+            self.timeout.lock().unwrap().check(vec!(ExpectedReply::PollContent, ExpectedReply::IBLT, ExpectedReply::Content, ExpectedReply::Get, ExpectedReply::ContentDigests));
+            // re-score and evict content whose funding term ran out, but only once per new tip
+            let tip = self.store.read().unwrap().get_tip();
+            if tip.is_some() && tip != self.decayed_tip {
+                match self.store.write().unwrap().decay_to_tip() {
+                    Ok(()) => self.decayed_tip = tip,
+                    Err(_) => debug!("failed to decay content weight to tip")
+                }
+            }
         }
     }
 